@@ -3,7 +3,11 @@
 //! This takes bits and pieces of `eval_context.rs` (parent module) and remashes
 //! them together to separate code compilation from code execution.
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+use libloading::{Library, Symbol};
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::{Digest, Sha256};
 
 use crate::{
     code_block::CodeBlock, eval_context::{Config, ContextState}, module::Module, rust_analyzer::RustAnalyzer, Error
@@ -11,6 +15,10 @@ use crate::{
 
 use super::{create_initial_config, VariableMoveState, VariableState};
 
+/// Name of the exported thunk that reclaims a buffer handed back by a
+/// `__evcxr_*` function (see [`SharedLibFunctions::code`]).
+const FREE_FN_NAME: &str = "__evcxr_free";
+
 #[derive(Debug, Clone)]
 pub struct FunctionArg {
     arg_name: String,
@@ -23,12 +31,152 @@ pub struct ParsedFunction {
     fn_body: String,
     inputs: Vec<FunctionArg>,
     outputs: Vec<FunctionArg>,
+    /// Names of `inputs` that `fn_body` writes through (`&mut`, reassignment,
+    /// or moved then rebound) rather than merely reads, so the marshalling
+    /// layer knows which ones also need to be passed back out.
+    written_inputs: Vec<String>,
+}
+
+/// The optimization level to build a `cdylib` with, mirroring `rustc`'s
+/// `-C opt-level` values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OptLevel {
+    O0,
+    O1,
+    O2,
+    O3,
+    S,
+    Z,
+}
+
+impl OptLevel {
+    fn as_str(self) -> &'static str {
+        match self {
+            OptLevel::O0 => "0",
+            OptLevel::O1 => "1",
+            OptLevel::O2 => "2",
+            OptLevel::O3 => "3",
+            OptLevel::S => "s",
+            OptLevel::Z => "z",
+        }
+    }
+}
+
+/// Build settings that influence how a `cdylib` is compiled: optimization
+/// level, debug info, and any extra `rustflags`. Defaults to `opt-level = 0`
+/// with debuginfo on, since the common case is a one-off interactive call;
+/// raise the profile with [`SharedLibFunctions::with_profile`] for a function
+/// that will be called repeatedly.
+#[derive(Debug, Clone)]
+pub struct BuildProfile {
+    opt_level: OptLevel,
+    debuginfo: bool,
+    rustflags: Vec<String>,
+}
+
+impl Default for BuildProfile {
+    fn default() -> Self {
+        Self {
+            opt_level: OptLevel::O0,
+            debuginfo: true,
+            rustflags: Vec::new(),
+        }
+    }
+}
+
+impl BuildProfile {
+    pub fn with_opt_level(mut self, opt_level: OptLevel) -> Self {
+        self.opt_level = opt_level;
+        self
+    }
+
+    pub fn with_debuginfo(mut self, debuginfo: bool) -> Self {
+        self.debuginfo = debuginfo;
+        self
+    }
+
+    /// Append extra `RUSTFLAGS` to pass to the compiler invocation, e.g.
+    /// `-C target-cpu=native`.
+    pub fn with_rustflags(mut self, rustflags: impl IntoIterator<Item = String>) -> Self {
+        self.rustflags.extend(rustflags);
+        self
+    }
+
+    /// Merge this profile's settings into the crate's `.cargo/config.toml`,
+    /// which `Module::write_config_toml` has already written under
+    /// `config.tmpdir`. `Config` itself carries no opt-level/debuginfo/
+    /// rustflags fields, so the profile is applied as a direct edit to the
+    /// emitted file rather than threaded through `Config`. Uses
+    /// [`merge_toml_table`] rather than appending raw text, since
+    /// `write_config_toml` may already have emitted a `[build]` table of its
+    /// own (e.g. for a target-dir setting) and TOML forbids redefining the
+    /// same table twice in one document.
+    fn write_to(&self, config: &Config) -> Result<(), Error> {
+        let config_toml_path = config.tmpdir.join(".cargo").join("config.toml");
+        merge_toml_table(
+            &config_toml_path,
+            "profile.dev",
+            &[
+                format!("opt-level = \"{}\"", self.opt_level.as_str()),
+                format!("debug = {}", self.debuginfo),
+            ],
+        )?;
+        if !self.rustflags.is_empty() {
+            let flags = self
+                .rustflags
+                .iter()
+                .map(|flag| format!("{flag:?}"))
+                .collect::<Vec<_>>()
+                .join(", ");
+            merge_toml_table(&config_toml_path, "build", &[format!("rustflags = [{flags}]")])?;
+        }
+        Ok(())
+    }
+}
+
+/// Where compiled `cdylib` artifacts are cached, and whether caching is used
+/// at all. Owned by [`SharedLibFunctions`] rather than `Config`, since the
+/// cache is a property of how a set of functions is compiled, not of the
+/// surrounding evaluation context.
+#[derive(Debug, Clone)]
+pub struct CacheOptions {
+    enabled: bool,
+    dir: PathBuf,
+}
+
+impl Default for CacheOptions {
+    /// Caches under the system temp directory by default, so repeated
+    /// compiles of unchanged functions are fast without any setup.
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            dir: std::env::temp_dir().join("evcxr_shared_lib_cache"),
+        }
+    }
+}
+
+impl CacheOptions {
+    /// Cache compiled artifacts under `dir` instead of the default location.
+    pub fn with_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.dir = dir.into();
+        self
+    }
+
+    /// Disable the cache: every compile invokes the compiler.
+    pub fn disabled() -> Self {
+        Self {
+            enabled: false,
+            ..Self::default()
+        }
+    }
 }
 
 /// A set of functions to be compiled into a shared library.
 #[derive(Debug, Clone, Default)]
 pub struct SharedLibFunctions {
     functions: Vec<ParsedFunction>,
+    profile: BuildProfile,
+    cache: CacheOptions,
 }
 
 impl SharedLibFunctions {
@@ -36,6 +184,18 @@ impl SharedLibFunctions {
         Self::default()
     }
 
+    /// Set the [`BuildProfile`] used to compile this set of functions.
+    pub fn with_profile(mut self, profile: BuildProfile) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    /// Set the [`CacheOptions`] used to compile this set of functions.
+    pub fn with_cache(mut self, cache: CacheOptions) -> Self {
+        self.cache = cache;
+        self
+    }
+
     /// Add a function to the set of functions to be compiled.
     ///
     /// # Arguments
@@ -51,50 +211,105 @@ impl SharedLibFunctions {
         fn_body: &str,
         scope: &[FunctionArg],
     ) -> Result<(), Error> {
-        // TODO: restrict the set of inputs.
-        // For now, we take the whole scope as input
-        let inputs = scope.to_owned();
+        let usage = find_variable_usage(fn_body, scope);
+        let inputs = scope
+            .iter()
+            .filter(|arg| usage.read.contains(&arg.arg_name))
+            .cloned()
+            .collect::<Vec<_>>();
+        let written_inputs = inputs
+            .iter()
+            .filter(|arg| usage.written.contains(&arg.arg_name))
+            .map(|arg| arg.arg_name.clone())
+            .collect();
         let outputs = find_outputs(fn_body, scope)?;
         self.functions.push(ParsedFunction {
             name: name.to_string(),
             fn_body: fn_body.to_string(),
             inputs,
             outputs,
+            written_inputs,
         });
         Ok(())
     }
 
-    /// Generate the code that can be compiled into a shared library
+    /// Generate the code that can be compiled into a shared library.
+    ///
+    /// The C ABI has no notion of Rust tuples or arbitrary non-`repr(C)`
+    /// types, so each function is *not* exported with its real signature.
+    /// Instead it is wrapped in a uniform `extern "C"` thunk that takes a
+    /// byte buffer of bincode-encoded inputs and hands back a heap-allocated
+    /// byte buffer of bincode-encoded outputs. This is the only part of the
+    /// boundary that is actually sound for arbitrary types; see
+    /// [`CompiledLib::call`] for the host side that speaks this protocol.
     pub fn code(&self) -> String {
-        let mut code = String::new();
+        let mut code = String::from(FREE_FN_CODE);
         for ParsedFunction {
             fn_body,
             name,
             inputs,
             outputs,
+            written_inputs,
         } in &self.functions
         {
-            let inputs = inputs
+            // Each name gets a trailing comma (matching `input_types` below)
+            // so that e.g. a single input still parses as the 1-tuple
+            // pattern `(a,)` rather than as `a` in parens. Inputs that are
+            // written through are bound `mut` and passed back out alongside
+            // the real outputs (see `outputs_types`/`outputs_vars` below).
+            let input_names = inputs
                 .iter()
-                .map(|FunctionArg { arg_name, arg_type }| format!("{}: {}", arg_name, arg_type))
+                .map(|FunctionArg { arg_name, .. }| {
+                    if written_inputs.contains(arg_name) {
+                        format!("mut {arg_name},")
+                    } else {
+                        format!("{arg_name},")
+                    }
+                })
                 .collect::<Vec<_>>()
-                .join(", ");
+                .join(" ");
+            let input_types = inputs
+                .iter()
+                .map(|FunctionArg { arg_type, .. }| format!("{arg_type},"))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let written_input_args = inputs
+                .iter()
+                .filter(|FunctionArg { arg_name, .. }| written_inputs.contains(arg_name));
             let outputs_types = outputs
                 .iter()
+                .chain(written_input_args.clone())
                 .map(|FunctionArg { arg_type, .. }| format!("{arg_type},"))
                 .collect::<Vec<_>>()
                 .join(" ");
             let outputs_vars = outputs
                 .iter()
+                .chain(written_input_args)
                 .map(|FunctionArg { arg_name, .. }| format!("{arg_name},"))
                 .collect::<Vec<_>>()
                 .join(" ");
             code.push_str(&format!(
                 r#"
-#[nomangle]
-pub extern "C" fn {name}({inputs}) -> ({outputs_types}) {{
-    {fn_body};
-    ({outputs_vars})
+#[no_mangle]
+pub unsafe extern "C" fn __evcxr_{name}(
+    in_ptr: *const u8,
+    in_len: usize,
+    out_ptr: *mut *mut u8,
+    out_len: *mut usize,
+) {{
+    let input_bytes = std::slice::from_raw_parts(in_ptr, in_len);
+    let ({input_names}): ({input_types}) =
+        bincode::deserialize(input_bytes).expect("evcxr: failed to decode inputs for {name}");
+    let result: ({outputs_types}) = {{
+        {fn_body};
+        ({outputs_vars})
+    }};
+    let mut encoded = bincode::serialize(&result)
+        .expect("evcxr: failed to encode outputs for {name}")
+        .into_boxed_slice();
+    *out_ptr = encoded.as_mut_ptr();
+    *out_len = encoded.len();
+    std::mem::forget(encoded);
 }}
 "#
             ))
@@ -103,6 +318,281 @@ pub extern "C" fn {name}({inputs}) -> ({outputs_types}) {{
     }
 }
 
+/// Source of the `__evcxr_free` thunk, emitted once per library regardless of
+/// how many functions it contains. It reclaims a buffer previously handed
+/// back through a `__evcxr_*` thunk's `out_ptr`/`out_len`.
+const FREE_FN_CODE: &str = r#"
+#[no_mangle]
+pub unsafe extern "C" fn __evcxr_free(ptr: *mut u8, len: usize) {
+    drop(Vec::from_raw_parts(ptr, len, len));
+}
+"#;
+
+/// A [`SharedLibFunctions`] set that has been compiled into a `cdylib` and
+/// loaded (via `dlopen`/`LoadLibrary`) into the current process, ready to be
+/// called into.
+pub struct CompiledLib {
+    library: Library,
+}
+
+impl CompiledLib {
+    /// Compile `functions` into a `cdylib` inside `config`'s temporary
+    /// directory and load the resulting shared library into this process.
+    ///
+    /// If `functions`' [`CacheOptions`] has caching enabled, the artifact is
+    /// looked up under its cache directory by a digest of everything that
+    /// determines its contents (see [`compute_digest`]) before invoking the
+    /// compiler, and stored there afterwards so identical functions are
+    /// never rebuilt.
+    pub fn compile_and_load(functions: &SharedLibFunctions, config: &Config) -> Result<Self, Error> {
+        let so_path = build_or_fetch_cached(functions, config)?;
+
+        // SAFETY: `so_path` is either the artifact of the `cargo build` we
+        // just ran above from `functions.code()`, or a previous build of the
+        // byte-for-byte identical code found in the cache, so its exported
+        // thunks match the calling convention that `call` below expects.
+        let library = unsafe { Library::new(so_path)? };
+        Ok(Self { library })
+    }
+
+    /// Call the function named `name` in this library.
+    ///
+    /// `inputs` is bincode-encoded and passed across the FFI boundary as a
+    /// byte buffer; the thunk's returned buffer is bincode-decoded into `O`
+    /// and freed via `__evcxr_free` before returning.
+    pub fn call<I, O>(&self, name: &str, inputs: &I) -> Result<O, Error>
+    where
+        I: Serialize,
+        O: DeserializeOwned,
+    {
+        let encoded = bincode::serialize(inputs)?;
+        let symbol_name = format!("__evcxr_{name}\0");
+
+        let (out_ptr, out_len) = unsafe {
+            let thunk: Symbol<unsafe extern "C" fn(*const u8, usize, *mut *mut u8, *mut usize)> =
+                self.library.get(symbol_name.as_bytes())?;
+            let mut out_ptr: *mut u8 = std::ptr::null_mut();
+            let mut out_len: usize = 0;
+            thunk(encoded.as_ptr(), encoded.len(), &mut out_ptr, &mut out_len);
+            (out_ptr, out_len)
+        };
+
+        let output = unsafe { std::slice::from_raw_parts(out_ptr, out_len) };
+        let result = bincode::deserialize(output);
+
+        unsafe {
+            let free: Symbol<unsafe extern "C" fn(*mut u8, usize)> =
+                self.library.get(FREE_FN_NAME.as_bytes())?;
+            free(out_ptr, out_len);
+        }
+
+        Ok(result?)
+    }
+}
+
+/// One function, compiled and loaded as its own single-function shared
+/// library so it can be rebuilt independently of its siblings. Returned by
+/// [`SharedLibFunctions::compile_all`].
+pub struct CompiledFn {
+    name: String,
+    lib: CompiledLib,
+}
+
+impl CompiledFn {
+    /// Call this function, see [`CompiledLib::call`].
+    pub fn call<I, O>(&self, inputs: &I) -> Result<O, Error>
+    where
+        I: Serialize,
+        O: DeserializeOwned,
+    {
+        self.lib.call(&self.name, inputs)
+    }
+}
+
+impl SharedLibFunctions {
+    /// Compile every function as an independent compilation unit, rebuilding
+    /// (when caching is enabled) only those whose [`fingerprint`] has changed
+    /// since the last call (as
+    /// recorded in the on-disk manifest under this set's [`CacheOptions`]), and driving the
+    /// rest through a bounded pool of worker threads so independent units
+    /// compile concurrently.
+    pub fn compile_all(&self, config: &Config) -> Result<Vec<CompiledFn>, Error> {
+        let mut manifest = FingerprintManifest::load(&self.cache);
+        let worker_count = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+            .min(self.functions.len().max(1));
+
+        let queue: std::sync::Mutex<std::collections::VecDeque<usize>> =
+            std::sync::Mutex::new((0..self.functions.len()).collect());
+        let results: Vec<std::sync::Mutex<Option<Result<UnitArtifact, Error>>>> =
+            (0..self.functions.len()).map(|_| std::sync::Mutex::new(None)).collect();
+
+        std::thread::scope(|scope| {
+            for _ in 0..worker_count {
+                scope.spawn(|| loop {
+                    let Some(index) = queue.lock().unwrap().pop_front() else {
+                        break;
+                    };
+                    let function = &self.functions[index];
+                    let result = fingerprint(function, &self.functions, &self.profile)
+                        .and_then(|fp| self.compile_unit(function, &fp, &manifest, config));
+                    *results[index].lock().unwrap() = Some(result);
+                });
+            }
+        });
+
+        let mut compiled = Vec::with_capacity(self.functions.len());
+        for (function, result) in self.functions.iter().zip(results) {
+            let unit = result.into_inner().unwrap().unwrap()?;
+            manifest.units.insert(
+                function.name.clone(),
+                FingerprintEntry {
+                    fingerprint: unit.fingerprint,
+                    artifact_path: unit.lib_path.clone(),
+                },
+            );
+            compiled.push(unit.compiled);
+        }
+        manifest.save(&self.cache)?;
+        Ok(compiled)
+    }
+
+    /// Compile (or, if caching is enabled and the function is unchanged and
+    /// still on disk, simply reload) a single
+    /// function's shared library, isolated in its own subdirectory of
+    /// `config.tmpdir` so that concurrently-compiling units (see
+    /// [`Self::compile_all`]'s worker pool) never share a `Cargo.toml`/`lib.rs`
+    /// with each other.
+    fn compile_unit(
+        &self,
+        function: &ParsedFunction,
+        fp: &str,
+        manifest: &FingerprintManifest,
+        config: &Config,
+    ) -> Result<UnitArtifact, Error> {
+        if let Some(entry) = manifest.units.get(&function.name).filter(|_| self.cache.enabled) {
+            if entry.fingerprint == fp && entry.artifact_path.exists() {
+                // SAFETY: `artifact_path` was produced by a previous,
+                // fingerprint-identical build of this same function, so its
+                // exported thunk still matches the calling convention.
+                let library = unsafe { Library::new(&entry.artifact_path)? };
+                return Ok(UnitArtifact {
+                    compiled: CompiledFn {
+                        name: function.name.clone(),
+                        lib: CompiledLib { library },
+                    },
+                    lib_path: entry.artifact_path.clone(),
+                    fingerprint: fp.to_string(),
+                });
+            }
+        }
+
+        let mut unit_config = config.clone();
+        unit_config.tmpdir = config.tmpdir.join("units").join(&function.name);
+        std::fs::create_dir_all(&unit_config.tmpdir)?;
+
+        let mut unit = SharedLibFunctions::new()
+            .with_profile(self.profile.clone())
+            .with_cache(self.cache.clone());
+        unit.functions.push(function.clone());
+        let lib_path = build_or_fetch_cached(&unit, &unit_config)?;
+        // SAFETY: see `compile_and_load`.
+        let library = unsafe { Library::new(&lib_path)? };
+        Ok(UnitArtifact {
+            compiled: CompiledFn {
+                name: function.name.clone(),
+                lib: CompiledLib { library },
+            },
+            lib_path,
+            fingerprint: fp.to_string(),
+        })
+    }
+}
+
+/// Result of compiling (or reloading) a single function's unit, carrying the
+/// artifact path and fingerprint alongside the loaded library so
+/// [`SharedLibFunctions::compile_all`] can record both in the fingerprint
+/// manifest without recomputing the fingerprint (and re-shelling out to
+/// `rustc --version`) a second time.
+struct UnitArtifact {
+    compiled: CompiledFn,
+    lib_path: PathBuf,
+    fingerprint: String,
+}
+
+/// Fingerprint a single compilation unit: its body, its input/output
+/// signatures, the names, bodies and signatures of any sibling functions it
+/// references as whole identifiers (its dependency set, so a change to a
+/// function it calls directly also invalidates it), the [`BuildProfile`] it
+/// will be compiled with, and the
+/// active `rustc` version — anything that can change the bytes of the
+/// resulting artifact without changing `fn_body` itself must be folded in
+/// here, or a stale unit can be served back through
+/// [`SharedLibFunctions::compile_unit`]'s manifest fast-path.
+fn fingerprint(
+    function: &ParsedFunction,
+    all_functions: &[ParsedFunction],
+    profile: &BuildProfile,
+) -> Result<String, Error> {
+    let mut hasher = Sha256::new();
+    hasher.update(function.fn_body.as_bytes());
+    for FunctionArg { arg_name, arg_type } in function.inputs.iter().chain(&function.outputs) {
+        hasher.update(arg_name.as_bytes());
+        hasher.update(arg_type.as_bytes());
+    }
+    for other in all_functions {
+        if other.name != function.name && references_identifier(&function.fn_body, &other.name) {
+            hasher.update(other.name.as_bytes());
+            hasher.update(other.fn_body.as_bytes());
+            for FunctionArg { arg_name, arg_type } in other.inputs.iter().chain(&other.outputs) {
+                hasher.update(arg_name.as_bytes());
+                hasher.update(arg_type.as_bytes());
+            }
+        }
+    }
+    hasher.update(profile.opt_level.as_str().as_bytes());
+    hasher.update([profile.debuginfo as u8]);
+    for flag in &profile.rustflags {
+        hasher.update(flag.as_bytes());
+    }
+    hasher.update(rustc_version()?.as_bytes());
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// On-disk record of the fingerprint and artifact path each function had the
+/// last time it was compiled, so [`SharedLibFunctions::compile_all`] can tell
+/// which units are stale across process restarts.
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+struct FingerprintManifest {
+    units: std::collections::HashMap<String, FingerprintEntry>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct FingerprintEntry {
+    fingerprint: String,
+    artifact_path: PathBuf,
+}
+
+impl FingerprintManifest {
+    fn path(cache: &CacheOptions) -> PathBuf {
+        cache.dir.join("fingerprints.json")
+    }
+
+    fn load(cache: &CacheOptions) -> Self {
+        std::fs::read(Self::path(cache))
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self, cache: &CacheOptions) -> Result<(), Error> {
+        std::fs::create_dir_all(&cache.dir)?;
+        std::fs::write(Self::path(cache), serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+}
+
 impl ContextState {
     /// Put variables in scope and mark them as "old" variables
     fn load_scope(&mut self, scope: &[FunctionArg]) {
@@ -158,6 +648,202 @@ fn find_outputs(code: &str, scope: &[FunctionArg]) -> Result<Vec<FunctionArg>, E
     Ok(outputs)
 }
 
+/// Which `scope` identifiers `fn_body` actually references (its read set),
+/// and which of those it writes through rather than merely reads.
+struct VariableUsage {
+    /// Scope identifiers that `fn_body` mentions as a whole word anywhere.
+    /// Shadowing is not tracked: a name used only after a local `let`
+    /// rebinds it is still included. These, and only these, become `inputs`.
+    read: std::collections::HashSet<String>,
+    /// The subset of `read` that `fn_body` writes through (`&mut`,
+    /// reassignment, or moved then rebound), rather than only reading.
+    written: std::collections::HashSet<String>,
+}
+
+/// Compute the read set of `fn_body` over `scope`: a lexical scan for each
+/// scope identifier referenced as a whole word, plus a heuristic for whether
+/// it's written through (`&mut ident`, or `ident` followed by `=` or a
+/// compound assignment operator, but not by `==` or `=>`). This is a textual
+/// approximation rather than a real dataflow analysis (rust-analyzer is not
+/// consulted), so it can over-approximate in either direction for
+/// sufficiently unusual code; it decides which scope variables become
+/// `inputs` and which of those are passed back out.
+fn find_variable_usage(code: &str, scope: &[FunctionArg]) -> VariableUsage {
+    let mut read = std::collections::HashSet::new();
+    let mut written = std::collections::HashSet::new();
+    for FunctionArg { arg_name, .. } in scope {
+        if references_identifier(code, arg_name) {
+            read.insert(arg_name.clone());
+            if is_written_through(code, arg_name) {
+                written.insert(arg_name.clone());
+            }
+        }
+    }
+    VariableUsage { read, written }
+}
+
+/// Whether `code` references `ident` as a whole identifier, not merely as a
+/// substring of some longer identifier.
+fn references_identifier(code: &str, ident: &str) -> bool {
+    let mut search_from = 0;
+    while let Some(offset) = code[search_from..].find(ident) {
+        let start = search_from + offset;
+        let end = start + ident.len();
+        let before_ok = code[..start].chars().next_back().map_or(true, |c| !is_ident_char(c));
+        let after_ok = code[end..].chars().next().map_or(true, |c| !is_ident_char(c));
+        if before_ok && after_ok {
+            return true;
+        }
+        search_from = start + 1;
+    }
+    false
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Assignment operators that write through their left-hand side. Only
+/// whether the text after an identifier starts with one of them is checked,
+/// so their order does not matter.
+const ASSIGN_OPS: &[&str] = &[
+    "<<=", ">>=", "+=", "-=", "*=", "/=", "%=", "&=", "|=", "^=", "=",
+];
+
+/// Whether `code` writes through `ident` (`&mut ident`, or `ident` on the
+/// left of a plain or compound assignment) rather than only reading it.
+fn is_written_through(code: &str, ident: &str) -> bool {
+    if code.contains(&format!("&mut {ident}")) {
+        return true;
+    }
+    let mut search_from = 0;
+    while let Some(offset) = code[search_from..].find(ident) {
+        let start = search_from + offset;
+        let end = start + ident.len();
+        let before_ok = code[..start].chars().next_back().map_or(true, |c| !is_ident_char(c));
+        if before_ok {
+            let rest = code[end..].trim_start();
+            // `==` and `=>` must not be mistaken for `=`; every other operator
+            // in `ASSIGN_OPS` is unambiguous since none of them are a prefix
+            // of a longer non-assignment operator.
+            if !rest.starts_with("==")
+                && !rest.starts_with("=>")
+                && ASSIGN_OPS.iter().any(|op| rest.starts_with(op))
+            {
+                return true;
+            }
+        }
+        search_from = start + 1;
+    }
+    false
+}
+
+/// Compile `functions` into a `cdylib`, or return the path of a previously
+/// compiled artifact for the same content, without loading it.
+///
+/// This is the shared implementation behind [`CompiledLib::compile_and_load`]
+/// (one shared library for a whole [`SharedLibFunctions`] set) and
+/// [`SharedLibFunctions::compile_all`] (one shared library per function).
+fn build_or_fetch_cached(functions: &SharedLibFunctions, config: &Config) -> Result<PathBuf, Error> {
+    let module = Module::new()?;
+    let state = ContextState::new(config.clone());
+    module.write_cargo_toml(&state)?;
+    module.write_config_toml(&state)?;
+    // The generated thunks (see `SharedLibFunctions::code`) always
+    // `bincode::serialize`/`deserialize` across the FFI boundary, so the
+    // compiled crate needs both as real dependencies regardless of what
+    // `write_cargo_toml` emitted for `state`'s own scope.
+    merge_toml_table(
+        &config.tmpdir.join("Cargo.toml"),
+        "dependencies",
+        &[
+            r#"bincode = "1""#.to_string(),
+            r#"serde = { version = "1", features = ["derive"] }"#.to_string(),
+        ],
+    )?;
+    functions.profile.write_to(config)?;
+    module.write_lib_rs(&functions.code())?;
+
+    if functions.cache.enabled {
+        let digest = compute_digest(functions, config)?;
+        let cached_path = functions.cache.dir.join(format!("{digest}.so"));
+        if !cached_path.exists() {
+            let built_path = module.build_cdylib(config)?;
+            std::fs::create_dir_all(&functions.cache.dir)?;
+            std::fs::copy(&built_path, &cached_path)?;
+        }
+        Ok(cached_path)
+    } else {
+        module.build_cdylib(config)
+    }
+}
+
+/// Compute a content-addressed digest over everything that determines the
+/// `cdylib` artifact `functions` would compile to: each function's body and
+/// input/output signatures, the emitted [`SharedLibFunctions::code`], the
+/// active `rustc` version, and the `Cargo.toml`/`config.toml` contents
+/// `Module::write_cargo_toml`/`write_config_toml` wrote for this build.
+///
+/// Two calls with byte-for-byte identical inputs are guaranteed to produce
+/// the same digest, so it doubles as the cache key in
+/// [`CompiledLib::compile_and_load`].
+fn compute_digest(functions: &SharedLibFunctions, config: &Config) -> Result<String, Error> {
+    let mut hasher = Sha256::new();
+    for ParsedFunction {
+        name,
+        fn_body,
+        inputs,
+        outputs,
+        ..
+    } in &functions.functions
+    {
+        hasher.update(name.as_bytes());
+        hasher.update(fn_body.as_bytes());
+        for FunctionArg { arg_name, arg_type } in inputs.iter().chain(outputs) {
+            hasher.update(arg_name.as_bytes());
+            hasher.update(arg_type.as_bytes());
+        }
+    }
+    hasher.update(functions.code().as_bytes());
+    hasher.update(rustc_version()?.as_bytes());
+    hasher.update(std::fs::read(config.tmpdir.join("Cargo.toml"))?);
+    hasher.update(std::fs::read(config.tmpdir.join(".cargo").join("config.toml"))?);
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// The version string of the `rustc` that will be used to compile the
+/// `cdylib`, used as part of the cache key so a toolchain upgrade doesn't
+/// serve a stale artifact.
+fn rustc_version() -> Result<String, Error> {
+    let output = std::process::Command::new("rustc").arg("--version").output()?;
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Merge `entries` into the `[section]` table of the TOML file at `path`,
+/// inserting the table if it isn't already present rather than blindly
+/// appending a second `[section]` header after it — TOML forbids redefining
+/// the same table twice in one document, so naive concatenation breaks as
+/// soon as `path` already contains `section` (e.g. a `[build]` table that
+/// `Module::write_config_toml` emits for its own purposes).
+fn merge_toml_table(path: &Path, section: &str, entries: &[String]) -> Result<(), Error> {
+    let contents = std::fs::read_to_string(path).unwrap_or_default();
+    let header = format!("[{section}]");
+    let mut lines: Vec<String> = contents.lines().map(str::to_string).collect();
+    if let Some(header_line) = lines.iter().position(|line| line.trim() == header) {
+        for (offset, entry) in entries.iter().enumerate() {
+            lines.insert(header_line + 1 + offset, entry.clone());
+        }
+    } else {
+        if !lines.is_empty() {
+            lines.push(String::new());
+        }
+        lines.push(header);
+        lines.extend(entries.iter().cloned());
+    }
+    std::fs::write(path, lines.join("\n") + "\n")?;
+    Ok(())
+}
+
 /// Create a config from a new temporary directory
 fn tmp_config() -> Result<Config, Error> {
     let tmpdir = tempfile::tempdir()?;
@@ -208,8 +894,44 @@ mod tests {
             .add_fn("add", "let b = 2;\n a + b", &scope)
             .unwrap();
         let code = shared_lib.code();
+        assert!(code.contains("__evcxr_free"));
         assert!(code
             .split('\n')
-            .any(|line| line == "pub extern \"C\" fn add(a: i32) -> (i32,) {"));
+            .any(|line| line == "pub unsafe extern \"C\" fn __evcxr_add("));
+        // A single input must still destructure as a 1-tuple pattern `(a,)`,
+        // not `(a)` (which would bind `a` to the whole 1-tuple).
+        assert!(code.contains("let (a,): (i32,) ="));
+    }
+
+    #[test]
+    fn test_add_fn_restricts_inputs_to_read_set() {
+        let scope = vec![
+            FunctionArg {
+                arg_name: "a".to_string(),
+                arg_type: "i32".to_string(),
+            },
+            FunctionArg {
+                arg_name: "unused".to_string(),
+                arg_type: "i32".to_string(),
+            },
+        ];
+        let mut shared_lib = SharedLibFunctions::new();
+        shared_lib
+            .add_fn("add", "let b = 2;\n a + b", &scope)
+            .unwrap();
+        let inputs = &shared_lib.functions[0].inputs;
+        assert_eq!(inputs.len(), 1);
+        assert_eq!(inputs[0].arg_name, "a");
+    }
+
+    #[test]
+    fn test_add_fn_detects_compound_assignment_as_written() {
+        let scope = vec![FunctionArg {
+            arg_name: "count".to_string(),
+            arg_type: "i32".to_string(),
+        }];
+        let mut shared_lib = SharedLibFunctions::new();
+        shared_lib.add_fn("incr", "count += 1;", &scope).unwrap();
+        assert_eq!(shared_lib.functions[0].written_inputs, vec!["count".to_string()]);
     }
 }